@@ -1,3 +1,7 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
 use crate::Color;
 
 
@@ -45,13 +49,34 @@ pub trait Canvas {
 		return self.get_old_unchecked(x, y);
 	}
 
-	fn iter() -> CanvasIterator<Self> {
+	fn iter() -> CanvasIterator<Self> where Self: Sized + 'static {
 		return CanvasIterator::new();
 	}
 
-	// fn iter_mut(&mut self) -> CanvasIteratorMut<Self> {
-	// 	return CanvasIteratorMut::new(self);
-	// }
+	/// Walks every valid button, letting `f` read its current color and return the color to set
+	/// it to. This is the mutable counterpart to `iter()`: a per-button handle holding `&mut` to
+	/// the canvas can't be handed out without aliasing that canvas, so instead the canvas drives
+	/// the walk and calls back into `f` for each valid coordinate.
+	fn map_buttons(&mut self, mut f: impl FnMut(CanvasButton<Self>, Color) -> Color) {
+		for y in 0..Self::BOUNDING_BOX_HEIGHT {
+			for x in 0..Self::BOUNDING_BOX_WIDTH {
+				if !Self::is_valid(x, y) { continue; }
+
+				let button = CanvasButton { x, y, phantom: std::marker::PhantomData };
+				let current_color = self.get_unchecked(x, y);
+				let new_color = f(button, current_color);
+				self.set_unchecked(x, y, new_color);
+			}
+		}
+	}
+
+	/// Iterates every valid button whose color differs from its last-flushed color. Implementors
+	/// can build a minimal SysEx/MIDI update batch from this instead of re-sending the whole grid
+	/// on every `flush`
+	fn changed_buttons(&self) -> impl Iterator<Item = CanvasButton<Self>> + '_
+	where Self: Sized + 'static, Color: PartialEq {
+		return Self::iter().filter(|button| button.get(self) != button.get_old(self));
+	}
 }
 
 // Next lines are canvas iteration stuff...
@@ -84,151 +109,107 @@ impl<C: Canvas + ?Sized> CanvasButton<C> {
 	}
 }
 
-pub struct CanvasIterator<C: Canvas + ?Sized> {
-	// These are on a valid state at the start, and right before the next valid state afterwards
-	x: u32,
-	y: u32,
+impl<C: Canvas + 'static> CanvasButton<C> {
+	/// Looks up the button at the given linear index into the canvas' valid positions, in the
+	/// same order `iter()` yields them. `None` if `i` is out of range
+	pub fn from_index(i: usize) -> Option<Self> {
+		let &(x, y) = valid_positions::<C>().get(i)?;
+		return Some(CanvasButton { x, y, phantom: std::marker::PhantomData });
+	}
+}
+
+// The set of valid (x, y) positions is fixed per `Canvas` type, so it only needs to be scanned
+// once per type and can then be shared and indexed into directly, instead of every `iter()`,
+// `nth()` or `from_index()` call re-walking the bounding box.
+//
+// Rust gives a generic function no way to have its own `static` per type parameter (a `static`
+// declared in here is one single instance shared across every `C`), so the tables are kept in a
+// process-wide registry keyed by `TypeId` instead. `from_index()` is on the hot path of mapping a
+// MIDI note back to its button, so the common case (the table for `C` already exists) only takes
+// a shared read lock to clone the `Arc` out; the `RwLock`'s write lock is only taken by a call
+// that finds no table for `C`, and the table is built only by the first of those.
+fn valid_positions<C: Canvas + 'static>() -> Arc<Vec<(u32, u32)>> {
+	static TABLES: OnceLock<RwLock<HashMap<TypeId, Arc<Vec<(u32, u32)>>>>> = OnceLock::new();
+	let tables = TABLES.get_or_init(|| RwLock::new(HashMap::new()));
+
+	if let Some(table) = tables.read().unwrap().get(&TypeId::of::<C>()) {
+		return table.clone();
+	}
+
+	let mut tables = tables.write().unwrap();
+	return tables.entry(TypeId::of::<C>()).or_insert_with(|| {
+		let mut positions = Vec::new();
+		for y in 0..C::BOUNDING_BOX_HEIGHT {
+			for x in 0..C::BOUNDING_BOX_WIDTH {
+				if C::is_valid(x, y) { positions.push((x, y)); }
+			}
+		}
+		Arc::new(positions)
+	}).clone();
+}
+
+pub struct CanvasIterator<C: Canvas + 'static> {
+	table: Arc<Vec<(u32, u32)>>,
+
+	// Next index to yield from the front, and one-past the last index to yield from the back.
+	// The iterator is empty once front == back
+	front: usize,
+	back: usize,
 
 	phantom: std::marker::PhantomData<C>, // dunno why rustc needs this but whatever
 }
 
-impl<C: Canvas + ?Sized> CanvasIterator<C> {
+impl<C: Canvas + 'static> CanvasIterator<C> {
 	fn new() -> Self {
-		let mut iter = CanvasIterator {
-			x: 0,
-			y: 0,
-			phantom: std::marker::PhantomData,
-		};
-		iter.find_next_valid(); // get to a valid state
-		return iter;
-	}
-
-	fn advance(&mut self) {
-		self.x += 1;
-		if self.x == C::BOUNDING_BOX_WIDTH {
-			self.x = 0;
-			self.y += 1;
-		}
+		let table = valid_positions::<C>();
+		let back = table.len();
+		return CanvasIterator { table, front: 0, back, phantom: std::marker::PhantomData };
 	}
 
-	// Returns false if there is no more valid state to go to
-	fn find_next_valid(&mut self) -> bool {
-		loop {
-			if self.y >= C::BOUNDING_BOX_HEIGHT { return false }
-			if C::is_valid(self.x, self.y) { return true }
-			// if the current position is not out of bounds but still invalid, let's continue
-			// searching
-			self.advance();
-		}
+	fn button_at(&self, index: usize) -> CanvasButton<C> {
+		let (x, y) = self.table[index];
+		return CanvasButton { x, y, phantom: std::marker::PhantomData };
 	}
 }
 
-impl<C: Canvas> Iterator for CanvasIterator<C> {
+impl<C: Canvas + 'static> Iterator for CanvasIterator<C> {
 	type Item = CanvasButton<C>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		let in_bounds = self.find_next_valid();
-		if !in_bounds { return None };
-
-		let value = CanvasButton {
-			x: self.x,
-			y: self.y,
-			phantom: std::marker::PhantomData,
-		};
+		if self.front == self.back { return None }
 
-		self.advance();
+		let value = self.button_at(self.front);
+		self.front += 1;
 
 		return Some(value);
 	}
-}
-
-/*// Wow that was a lot of code for canvas iteration. Let's just..... do it all again (:
-// I need to repeat all the code in order to have a mutable version.. ugh
-
-pub struct CanvasButtonMut<'a, C: Canvas + ?Sized> {
-	canvas: *mut C,
-	// canvas button coordinates MUST be valid!
-	x: u32,
-	y: u32,
-	phantom: std::marker::PhantomData<&'a C>,
-}
-
-impl<'a, C: Canvas + ?Sized> CanvasButtonMut<'a, C> {
-	pub fn x(&self) -> u32 { self.x }
-	pub fn y(&self) -> u32 { self.y }
 
-    pub fn get(&self) -> Color {
-		unsafe {
-			return (*self.canvas).get_unchecked(self.x, self.y);
-		}
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		let remaining = self.back - self.front;
+		return (remaining, Some(remaining));
 	}
 
-    pub fn get_old(&self) -> Color {
-		unsafe {
-			return (*self.canvas).get_old_unchecked(self.x, self.y);
-		}
-	}
-	
-    pub fn set(&mut self, color: Color) {
-		unsafe {
-			return (*self.canvas).set_unchecked(self.x, self.y, color);
-		}
+	fn nth(&mut self, n: usize) -> Option<Self::Item> {
+		// Skip n elements, then yield the one after, same semantics as the default Iterator::nth
+		self.front = self.front.saturating_add(n).min(self.back);
+		return self.next();
 	}
 }
 
-pub struct CanvasIteratorMut<'a, C: Canvas + ?Sized> {
-	canvas: &'a mut C,
-	// These are on a valid state at the start, and right before the next valid state afterwards
-	x: u32,
-	y: u32,
-}
+impl<C: Canvas + 'static> DoubleEndedIterator for CanvasIterator<C> {
+	fn next_back(&mut self) -> Option<Self::Item> {
+		if self.front == self.back { return None }
 
-impl<'a, C: Canvas + ?Sized> CanvasIteratorMut<'a, C> {
-	fn new(canvas: &'a mut C) -> Self {
-		let mut iter = CanvasIteratorMut {
-			canvas,
-			x: 0,
-			y: 0,
-		};
-		iter.find_next_valid(); // get to a valid state
-		return iter;
-	}
-
-	fn advance(&mut self) {
-		self.x += 1;
-		if self.x == C::BOUNDING_BOX_WIDTH {
-			self.y += 1;
-		}
-	}
+		self.back -= 1;
 
-	// Returns false if there is no more valid state to go to
-	fn find_next_valid(&mut self) -> bool {
-		loop {
-			if self.y >= C::BOUNDING_BOX_HEIGHT { return false }
-			if C::is_valid(self.x, self.y) { return true }
-			// if the current position is not out of bounds but still invalid, let's continue
-			// searching
-			self.advance();
-		}
+		return Some(self.button_at(self.back));
 	}
 }
 
-impl<'a, C: Canvas> Iterator for CanvasIteratorMut<'a, C> {
-	type Item = CanvasButtonMut<'a, C>;
-
-	fn next(&mut self) -> Option<Self::Item> {
-		let in_bounds = self.find_next_valid();
-		if !in_bounds { return None };
-
-		let value = CanvasButtonMut {
-			canvas: self.canvas as *mut _,
-			x: self.x,
-			y: self.y,
-			phantom: std::marker::PhantomData,
-		};
-
-		self.advance();
-
-		return Some(value);
+impl<C: Canvas + 'static> ExactSizeIterator for CanvasIterator<C> {
+	fn len(&self) -> usize {
+		return self.back - self.front;
 	}
-}*/
\ No newline at end of file
+}
+
+impl<C: Canvas + 'static> std::iter::FusedIterator for CanvasIterator<C> {}
\ No newline at end of file